@@ -0,0 +1,394 @@
+//! `TypeSystem::Dependent` (Dhall-style) checker over the F-omega
+//! representation.
+//!
+//! Types and terms share one syntax here, so `Π(x:A). B` is checked against
+//! an application by substituting the argument into `B` and comparing
+//! normal forms for definitional equality (normalization-by-evaluation),
+//! the way Dhall computes types from values. Only total, terminating
+//! programs type-check: there is no fixpoint/`Y`-combinator form, so
+//! normalization always halts.
+
+use crate::{fomega_capnp, PipelineConfig, PipelineError, PipelineResult, SourceSpan, TypeSystem};
+use std::rc::Rc;
+
+/// A node in the unified term/type language, paired with the `SourceSpan`
+/// it was lowered from so a type error can point at the specific
+/// subexpression that failed rather than only the enclosing program.
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: SourceSpan,
+}
+
+impl<T> Spanned<T> {
+    fn new(node: T, span: SourceSpan) -> Self {
+        Self { node, span }
+    }
+}
+
+/// Because this is dependently typed, `Pi`/`Lam`/`App` classify both values
+/// and the types of values.
+#[derive(Debug, Clone)]
+pub enum ExprNode {
+    /// A bound variable, referenced by de Bruijn index (0 = innermost).
+    Var(usize),
+    /// The type of types at `level` (`Type` = level 0, `Kind` = level 1, ...).
+    Universe(usize),
+    /// `Π(x:A). B` — a dependent function type; `B` may mention `x`.
+    Pi(Rc<Expr>, Rc<Expr>),
+    /// `λx. body`.
+    Lam(Rc<Expr>),
+    /// `f a`.
+    App(Rc<Expr>, Rc<Expr>),
+    /// An opaque base type or literal, carried through unchanged.
+    Base(String),
+}
+
+/// An expression lowered from `fomega_capnp`, still carrying its source
+/// span.
+pub type Expr = Spanned<ExprNode>;
+
+/// A term in weak-head normal form: either a canonical value or a neutral
+/// term stuck on a free variable. Splitting these is what makes
+/// normalization-by-evaluation total on well-scoped terms.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Universe(usize),
+    Pi(Rc<Value>, Closure),
+    Lam(Closure),
+    Base(String),
+    Neutral(Neutral),
+}
+
+#[derive(Debug, Clone)]
+pub enum Neutral {
+    Var(usize),
+    App(Box<Neutral>, Rc<Value>),
+}
+
+/// An expression paired with the environment it closes over; applied
+/// lazily so evaluation only does as much work as equality-checking
+/// actually demands.
+#[derive(Debug, Clone)]
+pub struct Closure {
+    env: Env,
+    body: Rc<Expr>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Env(Vec<Rc<Value>>);
+
+impl Env {
+    fn get(&self, index: usize) -> Option<Rc<Value>> {
+        self.0.iter().rev().nth(index).cloned()
+    }
+
+    fn extend(&self, value: Rc<Value>) -> Env {
+        let mut values = self.0.clone();
+        values.push(value);
+        Env(values)
+    }
+}
+
+/// Typing context: the type of each bound variable, in the same order as
+/// `Env` so indices line up.
+#[derive(Debug, Clone, Default)]
+struct Context(Vec<Rc<Value>>);
+
+impl Context {
+    fn lookup(&self, index: usize) -> Option<Rc<Value>> {
+        self.0.iter().rev().nth(index).cloned()
+    }
+
+    fn extend(&self, ty: Rc<Value>) -> Context {
+        let mut types = self.0.clone();
+        types.push(ty);
+        Context(types)
+    }
+
+    fn depth(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// A type error at a specific source location.
+fn type_error(span: &SourceSpan, message: impl std::fmt::Display) -> PipelineError {
+    PipelineError::Type {
+        message: format!("{message} (at {}:{}:{})", span.file, span.start_line, span.start_column),
+    }
+}
+
+/// Evaluate an expression to a value under `env`.
+fn eval(expr: &Expr, env: &Env) -> PipelineResult<Rc<Value>> {
+    let value = match &expr.node {
+        ExprNode::Var(i) => {
+            return env.get(*i).ok_or_else(|| type_error(&expr.span, format!("unbound variable index {i}")))
+        }
+        ExprNode::Universe(level) => Value::Universe(*level),
+        ExprNode::Pi(a, b) => Value::Pi(eval(a, env)?, Closure { env: env.clone(), body: b.clone() }),
+        ExprNode::Lam(body) => Value::Lam(Closure { env: env.clone(), body: body.clone() }),
+        ExprNode::App(f, a) => return apply(expr, eval(f, env)?, eval(a, env)?),
+        ExprNode::Base(name) => Value::Base(name.clone()),
+    };
+    Ok(Rc::new(value))
+}
+
+/// β-reduce `func arg`, the substitution step that lets a `Pi` type be
+/// checked against an application by substituting the argument into the
+/// result family. `site` is only used to locate a non-function application.
+fn apply(site: &Expr, func: Rc<Value>, arg: Rc<Value>) -> PipelineResult<Rc<Value>> {
+    match func.as_ref() {
+        Value::Lam(closure) => eval(&closure.body, &closure.env.extend(arg)),
+        Value::Neutral(n) => Ok(Rc::new(Value::Neutral(Neutral::App(Box::new(n.clone()), arg)))),
+        other => Err(type_error(&site.span, format!("cannot apply non-function value {other:?}"))),
+    }
+}
+
+fn apply_closure(closure: &Closure, arg: Rc<Value>) -> PipelineResult<Rc<Value>> {
+    eval(&closure.body, &closure.env.extend(arg))
+}
+
+/// A term in normal form, used only to compare two `Value`s structurally
+/// for definitional equality. Synthesized by `quote` rather than lowered
+/// from the program, so unlike `Expr` it carries no `SourceSpan`.
+#[derive(Debug, Clone)]
+enum NormalTerm {
+    Var(usize),
+    Universe(usize),
+    Pi(Rc<NormalTerm>, Rc<NormalTerm>),
+    Lam(Rc<NormalTerm>),
+    App(Rc<NormalTerm>, Rc<NormalTerm>),
+    Base(String),
+}
+
+/// Read a value back into a term in normal form at binding depth `depth`,
+/// so two values can be compared for definitional equality structurally.
+fn quote(depth: usize, value: &Value) -> PipelineResult<NormalTerm> {
+    Ok(match value {
+        Value::Universe(level) => NormalTerm::Universe(*level),
+        Value::Base(name) => NormalTerm::Base(name.clone()),
+        Value::Pi(a, closure) => {
+            let a_term = quote(depth, a)?;
+            let fresh = Rc::new(Value::Neutral(Neutral::Var(depth)));
+            let b_value = apply_closure(closure, fresh)?;
+            let b_term = quote(depth + 1, &b_value)?;
+            NormalTerm::Pi(Rc::new(a_term), Rc::new(b_term))
+        }
+        Value::Lam(closure) => {
+            let fresh = Rc::new(Value::Neutral(Neutral::Var(depth)));
+            let body_value = apply_closure(closure, fresh)?;
+            let body_term = quote(depth + 1, &body_value)?;
+            NormalTerm::Lam(Rc::new(body_term))
+        }
+        Value::Neutral(n) => quote_neutral(depth, n)?,
+    })
+}
+
+fn quote_neutral(depth: usize, neutral: &Neutral) -> PipelineResult<NormalTerm> {
+    Ok(match neutral {
+        // De Bruijn *level* to *index*: the binder `depth - 1 - level` steps
+        // out from the current position.
+        Neutral::Var(level) => NormalTerm::Var(depth - 1 - level),
+        Neutral::App(f, a) => NormalTerm::App(Rc::new(quote_neutral(depth, f)?), Rc::new(quote(depth, a)?)),
+    })
+}
+
+/// Definitional equality: normalize both sides and compare structurally.
+fn equal(depth: usize, a: &Value, b: &Value) -> PipelineResult<bool> {
+    Ok(terms_equal(&quote(depth, a)?, &quote(depth, b)?))
+}
+
+fn terms_equal(a: &NormalTerm, b: &NormalTerm) -> bool {
+    match (a, b) {
+        (NormalTerm::Var(i), NormalTerm::Var(j)) => i == j,
+        (NormalTerm::Universe(i), NormalTerm::Universe(j)) => i == j,
+        (NormalTerm::Base(x), NormalTerm::Base(y)) => x == y,
+        (NormalTerm::Pi(a1, b1), NormalTerm::Pi(a2, b2)) => terms_equal(a1, a2) && terms_equal(b1, b2),
+        (NormalTerm::Lam(b1), NormalTerm::Lam(b2)) => terms_equal(b1, b2),
+        (NormalTerm::App(f1, a1), NormalTerm::App(f2, a2)) => terms_equal(f1, f2) && terms_equal(a1, a2),
+        _ => false,
+    }
+}
+
+/// Infer the type of `expr`, as a `Value`.
+fn infer(ctx: &Context, env: &Env, expr: &Expr) -> PipelineResult<Rc<Value>> {
+    match &expr.node {
+        ExprNode::Var(i) => {
+            ctx.lookup(*i).ok_or_else(|| type_error(&expr.span, format!("unbound variable index {i}")))
+        }
+        ExprNode::Universe(level) => Ok(Rc::new(Value::Universe(level + 1))),
+        ExprNode::Base(_) => Ok(Rc::new(Value::Universe(0))),
+        ExprNode::Pi(a, b) => {
+            match infer(ctx, env, a)?.as_ref() {
+                Value::Universe(_) => {}
+                other => return Err(type_error(&a.span, format!("Pi domain is not a type: {other:?}"))),
+            }
+            let a_value = eval(a, env)?;
+            let fresh = Rc::new(Value::Neutral(Neutral::Var(ctx.depth())));
+            let inner_env = env.extend(fresh);
+            let inner_ctx = ctx.extend(a_value);
+            infer(&inner_ctx, &inner_env, b)
+        }
+        ExprNode::App(f, a) => {
+            let f_ty = infer(ctx, env, f)?;
+            match f_ty.as_ref() {
+                Value::Pi(a_ty, body) => {
+                    check(ctx, env, a, a_ty)?;
+                    // The key substitution step: Π(x:A). B is checked
+                    // against this application by substituting the
+                    // argument's *value* into B before normalizing.
+                    apply_closure(body, eval(a, env)?)
+                }
+                other => Err(type_error(&f.span, format!("applying non-function type {other:?}"))),
+            }
+        }
+        ExprNode::Lam(_) => Err(type_error(
+            &expr.span,
+            "cannot infer the type of a bare lambda; check it against an expected Pi type",
+        )),
+    }
+}
+
+/// Check `expr` against `expected`, the expected type as a `Value`.
+fn check(ctx: &Context, env: &Env, expr: &Expr, expected: &Value) -> PipelineResult<()> {
+    if let (ExprNode::Lam(body), Value::Pi(a_ty, b_closure)) = (&expr.node, expected) {
+        let fresh = Rc::new(Value::Neutral(Neutral::Var(ctx.depth())));
+        let b_ty = apply_closure(b_closure, fresh.clone())?;
+        let inner_ctx = ctx.extend(a_ty.clone());
+        let inner_env = env.extend(fresh);
+        return check(&inner_ctx, &inner_env, body, &b_ty);
+    }
+    let inferred = infer(ctx, env, expr)?;
+    if equal(ctx.depth(), &inferred, expected)? {
+        Ok(())
+    } else {
+        Err(type_error(&expr.span, format!("type mismatch: expected {expected:?}, inferred {inferred:?}")))
+    }
+}
+
+/// Read a `SourceSpan` off a lowered `fomega_capnp` node's span attachment.
+fn lower_span(span: fomega_capnp::source_span::Reader) -> PipelineResult<SourceSpan> {
+    Ok(SourceSpan::new(
+        span.get_file()?.to_string()?,
+        span.get_start_line(),
+        span.get_start_column(),
+        span.get_end_line(),
+        span.get_end_column(),
+    ))
+}
+
+/// Lower a `fomega_capnp` expression into the checker's own `Expr`
+/// representation, preserving its `SourceSpan` so later type errors can
+/// point at it.
+fn lower(reader: fomega_capnp::expr::Reader) -> PipelineResult<Expr> {
+    use fomega_capnp::expr::Which;
+    let span = lower_span(reader.get_span()?)?;
+    let node = match reader.which().map_err(|e| PipelineError::Type { message: format!("malformed F-omega node: {e}") })? {
+        Which::Var(index) => ExprNode::Var(index as usize),
+        Which::Universe(level) => ExprNode::Universe(level as usize),
+        Which::Pi(pi) => {
+            let pi = pi?;
+            ExprNode::Pi(Rc::new(lower(pi.get_domain()?)?), Rc::new(lower(pi.get_codomain()?)?))
+        }
+        Which::Lam(lam) => ExprNode::Lam(Rc::new(lower(lam?.get_body()?)?)),
+        Which::App(app) => {
+            let app = app?;
+            ExprNode::App(Rc::new(lower(app.get_function()?)?), Rc::new(lower(app.get_argument()?)?))
+        }
+        Which::Base(name) => ExprNode::Base(name?.to_string()?),
+    };
+    Ok(Spanned::new(node, span))
+}
+
+/// Type-check `message`, an encoded F-omega program, under the dependent
+/// checker. Only meaningful when `config.type_system == TypeSystem::Dependent`;
+/// the gradual/inferred/dynamic type systems dispatch to their own existing
+/// paths instead of calling this at all. Failures carry the `SourceSpan` of
+/// the specific subexpression that failed, read off the lowered `Expr` tree
+/// rather than the program as a whole.
+pub fn check_program(
+    message: &capnp::message::Reader<capnp::serialize::OwnedSegments>,
+    config: &PipelineConfig,
+) -> PipelineResult<()> {
+    debug_assert_eq!(config.type_system, TypeSystem::Dependent);
+    let root: fomega_capnp::expr::Reader =
+        message.get_root().map_err(|e| PipelineError::Type { message: format!("malformed F-omega message: {e}") })?;
+    let expr = lower(root)?;
+    infer(&Context::default(), &Env::default(), &expr).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: u32) -> SourceSpan {
+        SourceSpan::new("test.ll".to_string(), line, 1, line, 1)
+    }
+
+    fn var(i: usize, line: u32) -> Rc<Expr> {
+        Rc::new(Spanned::new(ExprNode::Var(i), span(line)))
+    }
+
+    fn universe(level: usize, line: u32) -> Rc<Expr> {
+        Rc::new(Spanned::new(ExprNode::Universe(level), span(line)))
+    }
+
+    fn base(name: &str, line: u32) -> Rc<Expr> {
+        Rc::new(Spanned::new(ExprNode::Base(name.to_string()), span(line)))
+    }
+
+    fn lam(body: Rc<Expr>, line: u32) -> Rc<Expr> {
+        Rc::new(Spanned::new(ExprNode::Lam(body), span(line)))
+    }
+
+    fn app(f: Rc<Expr>, a: Rc<Expr>, line: u32) -> Rc<Expr> {
+        Rc::new(Spanned::new(ExprNode::App(f, a), span(line)))
+    }
+
+    #[test]
+    fn identity_function_checks_against_its_pi_type() {
+        // (Pi (_: Nat). Nat) checked against \x. x
+        let ty = Value::Pi(
+            Rc::new(Value::Base("Nat".to_string())),
+            Closure { env: Env::default(), body: base("Nat", 1) },
+        );
+        let identity = lam(var(0, 2), 2);
+        check(&Context::default(), &Env::default(), &identity, &ty).expect("identity should check");
+    }
+
+    #[test]
+    fn applying_a_pi_substitutes_the_argument_into_the_codomain_family() {
+        // A variable of type `Pi(x: Type1). x` (the codomain family is just
+        // the bound variable) applied to `Type0` must infer `Type0`: the
+        // codomain was substituted with the argument's own value, not
+        // returned as an unevaluated reference to it.
+        let codomain_refers_to_bound_var = var(0, 1);
+        let pi_ty =
+            Value::Pi(Rc::new(Value::Universe(1)), Closure { env: Env::default(), body: codomain_refers_to_bound_var });
+        let ctx = Context::default().extend(Rc::new(pi_ty));
+        let applied = app(var(0, 2), universe(0, 3), 3);
+        let result_ty = infer(&ctx, &Env::default(), &applied).expect("application should infer a type");
+        match result_ty.as_ref() {
+            Value::Universe(0) => {}
+            other => panic!("expected Universe(0) substituted from the argument, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn mismatched_type_error_points_at_the_offending_subexpression() {
+        // \x. x checked against a non-Pi type fails, and the error carries
+        // the lambda's own span rather than some other node's.
+        let identity = lam(var(0, 42), 42);
+        let err = check(&Context::default(), &Env::default(), &identity, &Value::Base("Nat".to_string()))
+            .expect_err("a lambda cannot check against a non-function type");
+        let message = err.to_string();
+        assert!(message.contains("test.ll:42"), "expected span test.ll:42 in error, got: {message}");
+    }
+
+    #[test]
+    fn unbound_variable_error_carries_its_own_span() {
+        let expr = var(0, 7);
+        let err = infer(&Context::default(), &Env::default(), &expr).expect_err("var 0 is unbound in an empty context");
+        assert!(err.to_string().contains("test.ll:7"));
+    }
+}
@@ -0,0 +1,160 @@
+//! Structured diagnostics for editor/tool consumption.
+//!
+//! `PipelineError` carries a human-readable message but no `SourceSpan`, so a
+//! downstream tool can't render underlines or jump-to-error. `Diagnostic`
+//! bundles a severity, a message, and one or more spans, and
+//! `Pipeline::emit_diagnostics` serializes them as newline-delimited JSON —
+//! one object per line, like a compiler's machine-readable message stream —
+//! so an LSP or build tool can parse a stable schema instead of scraping
+//! formatted error text.
+
+use crate::{PipelineResult, SourceSpan};
+use std::io::Write;
+
+/// How serious a `Diagnostic` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+/// A single diagnostic: a severity, a message, and the source spans it
+/// points at (e.g. the offending expression plus a note pointing at its
+/// binding site).
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub spans: Vec<SourceSpan>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>, spans: Vec<SourceSpan>) -> Self {
+        Self { severity, message: message.into(), spans }
+    }
+
+    pub fn error(message: impl Into<String>, spans: Vec<SourceSpan>) -> Self {
+        Self::new(Severity::Error, message, spans)
+    }
+
+    pub fn warning(message: impl Into<String>, spans: Vec<SourceSpan>) -> Self {
+        Self::new(Severity::Warning, message, spans)
+    }
+
+    pub fn note(message: impl Into<String>, spans: Vec<SourceSpan>) -> Self {
+        Self::new(Severity::Note, message, spans)
+    }
+
+    /// Render as one JSON object, matching the
+    /// `{severity, message, spans:[{file,start_line,start_column,end_line,end_column}]}`
+    /// schema. Hand-rolled rather than pulled in via serde, since nothing
+    /// else in this crate needs a JSON dependency yet.
+    fn to_json_line(&self) -> String {
+        let spans: Vec<String> = self
+            .spans
+            .iter()
+            .map(|s| {
+                format!(
+                    "{{\"file\":{},\"start_line\":{},\"start_column\":{},\"end_line\":{},\"end_column\":{}}}",
+                    json_string(&s.file),
+                    s.start_line,
+                    s.start_column,
+                    s.end_line,
+                    s.end_column,
+                )
+            })
+            .collect();
+        format!(
+            "{{\"severity\":{},\"message\":{},\"spans\":[{}]}}",
+            json_string(self.severity.as_str()),
+            json_string(&self.message),
+            spans.join(","),
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl crate::Pipeline {
+    /// Write `diagnostics` to `writer` as newline-delimited JSON, one object
+    /// per line. Stages call this on stderr while keeping Cap'n Proto data
+    /// on stdout.
+    pub fn emit_diagnostics<W: Write>(writer: &mut W, diagnostics: &[Diagnostic]) -> PipelineResult<()> {
+        for diagnostic in diagnostics {
+            writeln!(writer, "{}", diagnostic.to_json_line())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_string_escapes_quotes_backslashes_and_control_characters() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a\"b"), "\"a\\\"b\"");
+        assert_eq!(json_string("a\\b"), "\"a\\\\b\"");
+        assert_eq!(json_string("a\nb\rc\td"), "\"a\\nb\\rc\\td\"");
+        assert_eq!(json_string("a\u{1}b"), "\"a\\u0001b\"");
+    }
+
+    #[test]
+    fn to_json_line_renders_severity_message_and_spans() {
+        let span = SourceSpan::new("a.ll".to_string(), 1, 2, 1, 5);
+        let diagnostic = Diagnostic::error("unbound variable \"x\"", vec![span]);
+        let line = diagnostic.to_json_line();
+        assert_eq!(
+            line,
+            "{\"severity\":\"error\",\"message\":\"unbound variable \\\"x\\\"\",\"spans\":[\
+             {\"file\":\"a.ll\",\"start_line\":1,\"start_column\":2,\"end_line\":1,\"end_column\":5}]}"
+        );
+    }
+
+    #[test]
+    fn to_json_line_renders_an_empty_spans_array_when_none_are_given() {
+        let diagnostic = Diagnostic::note("fyi", vec![]);
+        assert_eq!(diagnostic.to_json_line(), "{\"severity\":\"note\",\"message\":\"fyi\",\"spans\":[]}");
+    }
+
+    #[test]
+    fn emit_diagnostics_writes_one_json_object_per_line() {
+        let diagnostics =
+            vec![Diagnostic::warning("first", vec![]), Diagnostic::error("second", vec![])];
+        let mut out = Vec::new();
+        crate::Pipeline::emit_diagnostics(&mut out, &diagnostics).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], diagnostics[0].to_json_line());
+        assert_eq!(lines[1], diagnostics[1].to_json_line());
+    }
+}
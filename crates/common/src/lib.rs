@@ -12,61 +12,150 @@ pub mod fomega_capnp {
     include!(concat!(env!("OUT_DIR"), "/fomega_capnp.rs"));
 }
 
+pub mod dependent;
+pub mod diagnostics;
+pub mod protocol;
+pub mod repl;
+
 use anyhow::Result;
 use capnp::serialize;
-use std::io::Read;
+use capnp::serialize_packed;
+use std::io::Write;
+
+/// Wire encoding used to (de)serialize Cap'n Proto messages between pipeline
+/// stages. Both ends of a given pipe must agree on the format; `PipelineConfig`
+/// carries a single `wire_format` field so CLI stages only need to read it once
+/// at startup rather than negotiate per call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Cap'n Proto's standard zero-padded layout.
+    Unpacked,
+    /// Cap'n Proto's packed layout, which run-length-compresses zero bytes.
+    Packed,
+}
 
 /// Utilities for working with Cap'n Proto messages in the pipeline
 pub struct Pipeline;
 
 impl Pipeline {
     /// Read an AST message from stdin
-    pub fn read_ast() -> Result<capnp::message::Reader<capnp::serialize::OwnedSegments>> {
+    pub fn read_ast(format: WireFormat) -> Result<capnp::message::Reader<capnp::serialize::OwnedSegments>> {
         let stdin = std::io::stdin();
         let mut stdin = stdin.lock();
-        let message_reader = serialize::read_message(&mut stdin, capnp::message::ReaderOptions::new())?;
+        let message_reader = match format {
+            WireFormat::Unpacked => serialize::read_message(&mut stdin, capnp::message::ReaderOptions::new())?,
+            WireFormat::Packed => serialize_packed::read_message(&mut stdin, capnp::message::ReaderOptions::new())?,
+        };
         Ok(message_reader)
     }
 
     /// Write an AST message to stdout
-    pub fn write_ast(message: &capnp::message::Builder<capnp::message::HeapAllocator>) -> Result<()> {
+    pub fn write_ast(message: &capnp::message::Builder<capnp::message::HeapAllocator>, format: WireFormat) -> Result<()> {
         let stdout = std::io::stdout();
         let mut stdout = stdout.lock();
-        serialize::write_message(&mut stdout, message)?;
+        match format {
+            WireFormat::Unpacked => serialize::write_message(&mut stdout, message)?,
+            WireFormat::Packed => serialize_packed::write_message(&mut stdout, message)?,
+        }
         Ok(())
     }
 
     /// Read a TypedAST message from stdin
-    pub fn read_typed_ast() -> Result<capnp::message::Reader<capnp::serialize::OwnedSegments>> {
+    pub fn read_typed_ast(format: WireFormat) -> Result<capnp::message::Reader<capnp::serialize::OwnedSegments>> {
         let stdin = std::io::stdin();
         let mut stdin = stdin.lock();
-        let message_reader = serialize::read_message(&mut stdin, capnp::message::ReaderOptions::new())?;
+        let message_reader = match format {
+            WireFormat::Unpacked => serialize::read_message(&mut stdin, capnp::message::ReaderOptions::new())?,
+            WireFormat::Packed => serialize_packed::read_message(&mut stdin, capnp::message::ReaderOptions::new())?,
+        };
         Ok(message_reader)
     }
 
     /// Write a TypedAST message to stdout
-    pub fn write_typed_ast(message: &capnp::message::Builder<capnp::message::HeapAllocator>) -> Result<()> {
+    pub fn write_typed_ast(message: &capnp::message::Builder<capnp::message::HeapAllocator>, format: WireFormat) -> Result<()> {
         let stdout = std::io::stdout();
         let mut stdout = stdout.lock();
-        serialize::write_message(&mut stdout, message)?;
+        match format {
+            WireFormat::Unpacked => serialize::write_message(&mut stdout, message)?,
+            WireFormat::Packed => serialize_packed::write_message(&mut stdout, message)?,
+        }
         Ok(())
     }
 
     /// Read an F-omega message from stdin
-    pub fn read_fomega() -> Result<capnp::message::Reader<capnp::serialize::OwnedSegments>> {
+    pub fn read_fomega(format: WireFormat) -> Result<capnp::message::Reader<capnp::serialize::OwnedSegments>> {
         let stdin = std::io::stdin();
         let mut stdin = stdin.lock();
-        let message_reader = serialize::read_message(&mut stdin, capnp::message::ReaderOptions::new())?;
+        let message_reader = match format {
+            WireFormat::Unpacked => serialize::read_message(&mut stdin, capnp::message::ReaderOptions::new())?,
+            WireFormat::Packed => serialize_packed::read_message(&mut stdin, capnp::message::ReaderOptions::new())?,
+        };
         Ok(message_reader)
     }
 
     /// Write an F-omega message to stdout
-    pub fn write_fomega(message: &capnp::message::Builder<capnp::message::HeapAllocator>) -> Result<()> {
+    pub fn write_fomega(message: &capnp::message::Builder<capnp::message::HeapAllocator>, format: WireFormat) -> Result<()> {
         let stdout = std::io::stdout();
         let mut stdout = stdout.lock();
-        serialize::write_message(&mut stdout, message)?;
+        match format {
+            WireFormat::Unpacked => serialize::write_message(&mut stdout, message)?,
+            WireFormat::Packed => serialize_packed::write_message(&mut stdout, message)?,
+        }
         Ok(())
     }
+
+    /// Open an iterator over every message on stdin, in `format`, without
+    /// consuming the whole stream up front. Cap'n Proto's framing already
+    /// prefixes each message with its segment table, so repeated reads
+    /// naturally delimit messages; this just avoids paying process startup
+    /// per message and buffers the underlying reads.
+    pub fn read_stream(format: WireFormat) -> MessageStream<std::io::BufReader<std::io::StdinLock<'static>>> {
+        MessageStream { reader: std::io::BufReader::new(std::io::stdin().lock()), format }
+    }
+
+    /// Write a sequence of messages to stdout, in `format`, through a single
+    /// buffered writer, flushing once after the last message.
+    pub fn write_stream<I>(messages: I, format: WireFormat) -> PipelineResult<()>
+    where
+        I: IntoIterator<Item = capnp::message::Builder<capnp::message::HeapAllocator>>,
+    {
+        let stdout = std::io::stdout();
+        let mut writer = std::io::BufWriter::new(stdout.lock());
+        for message in messages {
+            match format {
+                WireFormat::Unpacked => serialize::write_message(&mut writer, &message)?,
+                WireFormat::Packed => serialize_packed::write_message(&mut writer, &message)?,
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Iterator over length-framed Cap'n Proto messages read from `reader`.
+/// Yields `Ok` for each successfully read message and stops (returns `None`)
+/// on a clean EOF between messages; an EOF in the middle of a message is
+/// surfaced as `PipelineError::Truncated` rather than ending the stream
+/// silently.
+pub struct MessageStream<R> {
+    reader: R,
+    format: WireFormat,
+}
+
+impl<R: std::io::BufRead> Iterator for MessageStream<R> {
+    type Item = PipelineResult<capnp::message::Reader<capnp::serialize::OwnedSegments>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = match self.format {
+            WireFormat::Unpacked => serialize::try_read_message(&mut self.reader, capnp::message::ReaderOptions::new()),
+            WireFormat::Packed => serialize_packed::try_read_message(&mut self.reader, capnp::message::ReaderOptions::new()),
+        };
+        match result {
+            Ok(Some(message)) => Some(Ok(message)),
+            Ok(None) => None,
+            Err(e) => Some(Err(PipelineError::Truncated(e))),
+        }
+    }
 }
 
 /// Error types for the pipeline
@@ -89,6 +178,15 @@ pub enum PipelineError {
 
     #[error("Evaluation error: {message}")]
     Evaluation { message: String },
+
+    #[error("truncated message: {0}")]
+    Truncated(capnp::Error),
+
+    #[error("protocol error: {message}")]
+    Protocol { message: String },
+
+    #[error("effect not permitted: {message}")]
+    Effect { message: String },
 }
 
 /// Common result type for pipeline components
@@ -121,6 +219,7 @@ pub struct PipelineConfig {
     pub type_system: TypeSystem,
     pub purity_level: PurityLevel,
     pub optimization_level: OptimizationLevel,
+    pub wire_format: WireFormat,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -145,6 +244,56 @@ pub enum OptimizationLevel {
     Aggressive,   // Maximum optimization
 }
 
+/// How much source-position information the AST→F-omega lowering keeps on
+/// each node's `SourceSpan` attachment, graded so an optimized build can
+/// ship a smaller Cap'n Proto payload instead of always carrying full spans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugInfo {
+    /// Drop the span entirely.
+    None,
+    /// Keep only the starting line.
+    LineOnly,
+    /// Keep the starting line and column, collapsed to a point. Not
+    /// produced by `for_optimization_level` today (its three levels map
+    /// onto `Full`/`LineOnly`/`None`); reserved for a future knob finer
+    /// grained than `OptimizationLevel`.
+    LineAndColumn,
+    /// Keep the full span, start and end.
+    Full,
+}
+
+impl DebugInfo {
+    /// Resolve the retention level for `optimization_level`. `Debug` keeps
+    /// full spans so evaluation errors can point at an exact column;
+    /// `Release` keeps line tables only; `Aggressive` drops spans entirely.
+    pub fn for_optimization_level(optimization_level: OptimizationLevel) -> Self {
+        match optimization_level {
+            OptimizationLevel::Debug => DebugInfo::Full,
+            OptimizationLevel::Release => DebugInfo::LineOnly,
+            OptimizationLevel::Aggressive => DebugInfo::None,
+        }
+    }
+
+    /// Apply this retention level to a span, as the lowering does per node.
+    pub fn apply(self, span: &SourceSpan) -> Option<SourceSpan> {
+        match self {
+            DebugInfo::None => None,
+            DebugInfo::LineOnly => Some(SourceSpan::point(span.file.clone(), span.start_line, 0)),
+            DebugInfo::LineAndColumn => Some(SourceSpan::point(span.file.clone(), span.start_line, span.start_column)),
+            DebugInfo::Full => Some(span.clone()),
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Debug-info retention for this config, resolved from
+    /// `optimization_level` rather than stored directly so the two can't
+    /// drift out of sync.
+    pub fn debug_info(&self) -> DebugInfo {
+        DebugInfo::for_optimization_level(self.optimization_level.clone())
+    }
+}
+
 impl Default for PipelineConfig {
     fn default() -> Self {
         Self {
@@ -152,6 +301,101 @@ impl Default for PipelineConfig {
             type_system: TypeSystem::Inferred,
             purity_level: PurityLevel::Sandbox,
             optimization_level: OptimizationLevel::Release,
+            wire_format: WireFormat::Unpacked,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn encode_empty_message(format: WireFormat) -> Vec<u8> {
+        let message = capnp::message::Builder::new_default();
+        let mut buf = Vec::new();
+        match format {
+            WireFormat::Unpacked => serialize::write_message(&mut buf, &message).unwrap(),
+            WireFormat::Packed => serialize_packed::write_message(&mut buf, &message).unwrap(),
+        }
+        buf
+    }
+
+    // `read_ast`/`write_ast`/`read_typed_ast`/`write_typed_ast`/`read_fomega`/
+    // `write_fomega` all share the exact same Unpacked/Packed framing as
+    // `MessageStream` and `encode_empty_message` above (just bound to
+    // stdin/stdout instead of a generic reader/writer), so these exercise
+    // that framing directly rather than duplicating a stdin/stdout harness
+    // per payload kind.
+    #[test]
+    fn packed_and_unpacked_encodings_of_the_same_message_differ() {
+        let unpacked = encode_empty_message(WireFormat::Unpacked);
+        let packed = encode_empty_message(WireFormat::Packed);
+        assert_ne!(unpacked, packed);
+    }
+
+    #[test]
+    fn unpacked_bytes_decode_back_via_the_same_framing_write_ast_uses() {
+        let bytes = encode_empty_message(WireFormat::Unpacked);
+        let message =
+            serialize::read_message(&mut Cursor::new(bytes), capnp::message::ReaderOptions::new()).unwrap();
+        message.get_root::<capnp::any_pointer::Reader>().unwrap();
+    }
+
+    #[test]
+    fn packed_bytes_decode_back_via_the_same_framing_write_ast_uses() {
+        let bytes = encode_empty_message(WireFormat::Packed);
+        let message =
+            serialize_packed::read_message(&mut Cursor::new(bytes), capnp::message::ReaderOptions::new()).unwrap();
+        message.get_root::<capnp::any_pointer::Reader>().unwrap();
+    }
+
+    #[test]
+    fn message_stream_yields_each_message_then_stops_cleanly_at_eof() {
+        let mut bytes = encode_empty_message(WireFormat::Unpacked);
+        bytes.extend(encode_empty_message(WireFormat::Unpacked));
+        let mut stream = MessageStream { reader: std::io::BufReader::new(Cursor::new(bytes)), format: WireFormat::Unpacked };
+        assert!(stream.next().expect("first message").is_ok());
+        assert!(stream.next().expect("second message").is_ok());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn message_stream_reports_a_truncated_message_distinctly_from_clean_eof() {
+        let mut bytes = encode_empty_message(WireFormat::Unpacked);
+        bytes.truncate(bytes.len() - 1);
+        let mut stream = MessageStream { reader: std::io::BufReader::new(Cursor::new(bytes)), format: WireFormat::Unpacked };
+        match stream.next() {
+            Some(Err(PipelineError::Truncated(_))) => {}
+            Some(Ok(_)) => panic!("expected a Truncated error, got a successfully parsed message"),
+            None => panic!("expected a Truncated error, got clean EOF"),
+        }
+    }
+
+    #[test]
+    fn debug_info_is_resolved_from_each_optimization_level() {
+        assert_eq!(DebugInfo::for_optimization_level(OptimizationLevel::Debug), DebugInfo::Full);
+        assert_eq!(DebugInfo::for_optimization_level(OptimizationLevel::Release), DebugInfo::LineOnly);
+        assert_eq!(DebugInfo::for_optimization_level(OptimizationLevel::Aggressive), DebugInfo::None);
+    }
+
+    #[test]
+    fn debug_info_apply_retains_or_strips_the_span_per_level() {
+        let span = SourceSpan::new("a.ll".to_string(), 3, 5, 4, 1);
+
+        assert_eq!(DebugInfo::None.apply(&span), None);
+
+        let line_only = DebugInfo::LineOnly.apply(&span).expect("LineOnly keeps a span");
+        assert_eq!((line_only.start_line, line_only.start_column), (3, 0));
+        assert_eq!((line_only.start_line, line_only.start_column), (line_only.end_line, line_only.end_column));
+
+        let line_and_column = DebugInfo::LineAndColumn.apply(&span).expect("LineAndColumn keeps a span");
+        assert_eq!((line_and_column.start_line, line_and_column.start_column), (3, 5));
+        assert_eq!(
+            (line_and_column.start_line, line_and_column.start_column),
+            (line_and_column.end_line, line_and_column.end_column)
+        );
+
+        assert_eq!(DebugInfo::Full.apply(&span), Some(span));
+    }
 }
\ No newline at end of file
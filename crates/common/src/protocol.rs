@@ -0,0 +1,380 @@
+//! Bidirectional stage protocol.
+//!
+//! A unidirectional `Pipeline` filter pays process-spawn cost per invocation.
+//! `Pipeline::serve` instead lets a driver spawn a stage once and exchange
+//! many requests/responses over its stdin/stdout, the way plugin hosts drive
+//! long-lived workers. Every exchange is wrapped in an `Envelope`, and the
+//! session opens with a `Hello` handshake so a newer driver can refuse an
+//! incompatible stage instead of producing garbled reads.
+
+use crate::{PipelineError, PipelineResult, WireFormat};
+use capnp::message::{Builder, HeapAllocator, Reader};
+use capnp::serialize::OwnedSegments;
+use capnp::{serialize, serialize_packed};
+use std::io::{BufRead, Write};
+
+/// Which pipeline stage a `Request`/`Response` payload carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    Ast,
+    TypedAst,
+    Fomega,
+}
+
+impl PayloadKind {
+    fn to_tag(self) -> u8 {
+        match self {
+            PayloadKind::Ast => 0,
+            PayloadKind::TypedAst => 1,
+            PayloadKind::Fomega => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> PipelineResult<Self> {
+        match tag {
+            0 => Ok(PayloadKind::Ast),
+            1 => Ok(PayloadKind::TypedAst),
+            2 => Ok(PayloadKind::Fomega),
+            other => Err(PipelineError::Protocol { message: format!("unknown payload kind tag {other}") }),
+        }
+    }
+}
+
+/// An envelope exchanged between a driver and a long-lived stage.
+pub enum Envelope {
+    /// Opening handshake: the protocol version and wire formats the sender
+    /// is able to read.
+    Hello { version: u32, supported_formats: Vec<WireFormat> },
+    /// A request carrying an AST/TypedAST/F-omega payload for the stage to
+    /// process.
+    Request { kind: PayloadKind, message: Reader<OwnedSegments> },
+    /// The stage's answer to a `Request`.
+    Response { kind: PayloadKind, message: Builder<HeapAllocator> },
+    /// A handshake or request failed. `serve` ends the session after a failed
+    /// handshake, but keeps serving after answering a failed request.
+    Error(PipelineError),
+}
+
+const TAG_HELLO: u8 = 0;
+const TAG_REQUEST: u8 = 1;
+const TAG_RESPONSE: u8 = 2;
+const TAG_ERROR: u8 = 3;
+
+fn format_to_tag(format: WireFormat) -> u8 {
+    match format {
+        WireFormat::Unpacked => 0,
+        WireFormat::Packed => 1,
+    }
+}
+
+fn format_from_tag(tag: u8) -> PipelineResult<WireFormat> {
+    match tag {
+        0 => Ok(WireFormat::Unpacked),
+        1 => Ok(WireFormat::Packed),
+        other => Err(PipelineError::Protocol { message: format!("unknown wire format tag {other}") }),
+    }
+}
+
+impl Envelope {
+    /// Read one envelope, or `None` on a clean EOF before the next one
+    /// starts.
+    pub fn read<R: BufRead>(reader: &mut R, format: WireFormat) -> PipelineResult<Option<Envelope>> {
+        let mut tag = [0u8; 1];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let envelope = match tag[0] {
+            TAG_HELLO => {
+                let version = read_u32(reader)?;
+                let count = read_u8(reader)?;
+                let mut supported_formats = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    supported_formats.push(format_from_tag(read_u8(reader)?)?);
+                }
+                Envelope::Hello { version, supported_formats }
+            }
+            TAG_REQUEST => {
+                let kind = PayloadKind::from_tag(read_u8(reader)?)?;
+                let message = read_payload(reader, format)?;
+                Envelope::Request { kind, message }
+            }
+            TAG_RESPONSE => {
+                let kind = PayloadKind::from_tag(read_u8(reader)?)?;
+                let message = read_payload(reader, format)?;
+                let mut builder = Builder::new_default();
+                builder.set_root_canonical(message.get_root::<capnp::any_pointer::Reader>()?)?;
+                Envelope::Response { kind, message: builder }
+            }
+            TAG_ERROR => {
+                let message = read_string(reader)?;
+                Envelope::Error(PipelineError::Protocol { message })
+            }
+            other => return Err(PipelineError::Protocol { message: format!("unknown envelope tag {other}") }),
+        };
+        Ok(Some(envelope))
+    }
+
+    /// Write one envelope.
+    pub fn write<W: Write>(&self, writer: &mut W, format: WireFormat) -> PipelineResult<()> {
+        match self {
+            Envelope::Hello { version, supported_formats } => {
+                writer.write_all(&[TAG_HELLO])?;
+                write_u32(writer, *version)?;
+                write_u8(writer, supported_formats.len() as u8)?;
+                for f in supported_formats {
+                    write_u8(writer, format_to_tag(*f))?;
+                }
+            }
+            Envelope::Request { kind, message } => {
+                writer.write_all(&[TAG_REQUEST])?;
+                write_u8(writer, kind.to_tag())?;
+                write_payload(writer, message, format)?;
+            }
+            Envelope::Response { kind, message } => {
+                writer.write_all(&[TAG_RESPONSE])?;
+                write_u8(writer, kind.to_tag())?;
+                match format {
+                    WireFormat::Unpacked => serialize::write_message(&mut *writer, message)?,
+                    WireFormat::Packed => serialize_packed::write_message(&mut *writer, message)?,
+                }
+            }
+            Envelope::Error(e) => {
+                writer.write_all(&[TAG_ERROR])?;
+                // `read` decodes every error as `Protocol`, so send a
+                // `Protocol` error's bare message to keep it from gaining a
+                // second "protocol error: " prefix on each hop.
+                let message = match e {
+                    PipelineError::Protocol { message } => message.clone(),
+                    other => other.to_string(),
+                };
+                write_string(writer, &message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_payload<R: BufRead>(reader: &mut R, format: WireFormat) -> PipelineResult<Reader<OwnedSegments>> {
+    let options = capnp::message::ReaderOptions::new();
+    let message = match format {
+        WireFormat::Unpacked => serialize::read_message(reader, options)?,
+        WireFormat::Packed => serialize_packed::read_message(reader, options)?,
+    };
+    Ok(message)
+}
+
+fn write_payload<W: Write>(writer: &mut W, message: &Reader<OwnedSegments>, format: WireFormat) -> PipelineResult<()> {
+    // Re-emit the already-decoded message in the negotiated wire format.
+    let mut builder = Builder::new_default();
+    builder.set_root_canonical(message.get_root::<capnp::any_pointer::Reader>()?)?;
+    match format {
+        WireFormat::Unpacked => serialize::write_message(writer, &builder)?,
+        WireFormat::Packed => serialize_packed::write_message(writer, &builder)?,
+    }
+    Ok(())
+}
+
+fn read_u8<R: BufRead>(reader: &mut R) -> PipelineResult<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn write_u8<W: Write>(writer: &mut W, value: u8) -> PipelineResult<()> {
+    writer.write_all(&[value])?;
+    Ok(())
+}
+
+fn read_u32<R: BufRead>(reader: &mut R) -> PipelineResult<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> PipelineResult<()> {
+    writer.write_all(&value.to_be_bytes())?;
+    Ok(())
+}
+
+fn read_string<R: BufRead>(reader: &mut R) -> PipelineResult<String> {
+    let len = read_u32(reader)? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| PipelineError::Protocol { message: e.to_string() })
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> PipelineResult<()> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+/// Protocol version this crate speaks. Bump when an envelope's shape changes
+/// in a way that an older stage could misread.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Wire formats this crate can read, in preference order.
+fn supported_formats() -> Vec<WireFormat> {
+    vec![WireFormat::Unpacked, WireFormat::Packed]
+}
+
+/// Decide the wire format to speak with a driver whose opening envelope was
+/// `hello`: reject a protocol-version mismatch, reject an empty intersection
+/// of supported formats, otherwise pick the first mutually supported format
+/// in this stage's preference order. Split out of `serve` so the actual
+/// negotiation logic is unit-testable without wiring up stdin/stdout.
+fn negotiate(hello: Envelope) -> PipelineResult<WireFormat> {
+    let Envelope::Hello { version, supported_formats: driver_formats } = hello else {
+        return Err(PipelineError::Protocol { message: "expected Hello as first envelope".into() });
+    };
+    if version != PROTOCOL_VERSION {
+        return Err(PipelineError::Protocol {
+            message: format!("incompatible protocol version: driver={version} stage={PROTOCOL_VERSION}"),
+        });
+    }
+    let ours = supported_formats();
+    ours.iter()
+        .find(|f| driver_formats.contains(f))
+        .copied()
+        .ok_or_else(|| PipelineError::Protocol { message: "no common wire format".into() })
+}
+
+impl crate::Pipeline {
+    /// Serve a long-lived stage over stdin/stdout: perform the `Hello`
+    /// handshake with the driver, then loop reading `Request` envelopes and
+    /// dispatching their payload to `handler`, writing back a `Response` or
+    /// `Error` envelope for each. Returns once the driver closes its end.
+    pub fn serve<F>(mut handler: F) -> PipelineResult<()>
+    where
+        F: FnMut(PayloadKind, Reader<OwnedSegments>) -> PipelineResult<(PayloadKind, Builder<HeapAllocator>)>,
+    {
+        let stdin = std::io::stdin();
+        let mut reader = std::io::BufReader::new(stdin.lock());
+        let stdout = std::io::stdout();
+        let mut writer = std::io::BufWriter::new(stdout.lock());
+
+        // Handshake: read the driver's Hello (assumed Unpacked, since the
+        // format itself hasn't been negotiated yet), then reply with ours
+        // and agree on the first mutually supported format.
+        let hello = Envelope::read(&mut reader, WireFormat::Unpacked)?
+            .ok_or_else(|| PipelineError::Protocol { message: "expected Hello as first envelope".into() })?;
+        let format = match negotiate(hello) {
+            Ok(format) => format,
+            Err(e) => {
+                Envelope::Error(PipelineError::Protocol { message: e.to_string() }).write(&mut writer, WireFormat::Unpacked)?;
+                writer.flush()?;
+                return Err(e);
+            }
+        };
+        Envelope::Hello { version: PROTOCOL_VERSION, supported_formats: supported_formats() }
+            .write(&mut writer, WireFormat::Unpacked)?;
+        writer.flush()?;
+
+        loop {
+            match Envelope::read(&mut reader, format)? {
+                None => return Ok(()),
+                Some(Envelope::Request { kind, message }) => {
+                    let envelope = match handler(kind, message) {
+                        Ok((kind, message)) => Envelope::Response { kind, message },
+                        Err(e) => Envelope::Error(e),
+                    };
+                    envelope.write(&mut writer, format)?;
+                    writer.flush()?;
+                }
+                Some(Envelope::Error(e)) => return Err(e),
+                Some(_) => return Err(PipelineError::Protocol { message: "expected Request after handshake".into() }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(envelope: Envelope, format: WireFormat) -> Envelope {
+        let mut bytes = Vec::new();
+        envelope.write(&mut bytes, format).unwrap();
+        let mut reader = std::io::BufReader::new(bytes.as_slice());
+        Envelope::read(&mut reader, format).unwrap().expect("one envelope")
+    }
+
+    #[test]
+    fn hello_round_trips_its_version_and_supported_formats() {
+        let envelope = Envelope::Hello {
+            version: PROTOCOL_VERSION,
+            supported_formats: vec![WireFormat::Unpacked, WireFormat::Packed],
+        };
+        match round_trip(envelope, WireFormat::Unpacked) {
+            Envelope::Hello { version, supported_formats } => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(supported_formats, vec![WireFormat::Unpacked, WireFormat::Packed]);
+            }
+            _ => panic!("expected Hello"),
+        }
+    }
+
+    fn empty_message_reader() -> Reader<OwnedSegments> {
+        let builder = Builder::new_default();
+        let mut bytes = Vec::new();
+        serialize::write_message(&mut bytes, &builder).unwrap();
+        serialize::read_message(bytes.as_slice(), capnp::message::ReaderOptions::new()).unwrap()
+    }
+
+    #[test]
+    fn request_round_trips_its_kind_and_payload_in_both_wire_formats() {
+        for format in [WireFormat::Unpacked, WireFormat::Packed] {
+            let envelope = Envelope::Request { kind: PayloadKind::Fomega, message: empty_message_reader() };
+            match round_trip(envelope, format) {
+                Envelope::Request { kind, .. } => assert_eq!(kind, PayloadKind::Fomega),
+                _ => panic!("expected Request"),
+            }
+        }
+    }
+
+    #[test]
+    fn error_round_trips_its_message() {
+        let envelope = Envelope::Error(PipelineError::Protocol { message: "boom".to_string() });
+        match round_trip(envelope, WireFormat::Unpacked) {
+            Envelope::Error(PipelineError::Protocol { message }) => assert_eq!(message, "boom"),
+            _ => panic!("expected Error"),
+        }
+    }
+
+    #[test]
+    fn read_reports_clean_eof_as_none() {
+        let mut reader = std::io::BufReader::new([].as_slice());
+        assert!(Envelope::read(&mut reader, WireFormat::Unpacked).unwrap().is_none());
+    }
+
+    #[test]
+    fn negotiate_picks_the_first_mutually_supported_format() {
+        let hello = Envelope::Hello {
+            version: PROTOCOL_VERSION,
+            supported_formats: vec![WireFormat::Packed, WireFormat::Unpacked],
+        };
+        assert_eq!(negotiate(hello).unwrap(), WireFormat::Unpacked);
+    }
+
+    #[test]
+    fn negotiate_rejects_a_protocol_version_mismatch() {
+        let hello = Envelope::Hello { version: PROTOCOL_VERSION + 1, supported_formats: vec![WireFormat::Unpacked] };
+        let err = negotiate(hello).unwrap_err();
+        assert!(err.to_string().contains("incompatible protocol version"), "got: {err}");
+    }
+
+    #[test]
+    fn negotiate_rejects_no_common_wire_format() {
+        let hello = Envelope::Hello { version: PROTOCOL_VERSION, supported_formats: vec![] };
+        let err = negotiate(hello).unwrap_err();
+        assert!(err.to_string().contains("no common wire format"), "got: {err}");
+    }
+
+    #[test]
+    fn payload_kind_round_trips_through_its_tag() {
+        for kind in [PayloadKind::Ast, PayloadKind::TypedAst, PayloadKind::Fomega] {
+            assert_eq!(PayloadKind::from_tag(kind.to_tag()).unwrap(), kind);
+        }
+        assert!(PayloadKind::from_tag(99).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+//! Interactive REPL front-end.
+//!
+//! Drives the pipeline in-process, one line at a time — parse → type-check →
+//! lower to F-omega → evaluate — instead of the batch pipeline's per-stage
+//! process spawning. The stage implementations themselves live in their own
+//! crates (parser, checker, evaluator); `Repl` is generic over a `Stages`
+//! implementation so this crate doesn't need to depend on any of them.
+
+use crate::{OptimizationLevel, PipelineConfig, PipelineError, PipelineResult, PurityLevel, TypeSystem};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// A parsed AST, typed AST, F-omega term, or evaluated result, as rendered
+/// by the stage that produced it. Kept opaque here since this crate doesn't
+/// own any of those representations.
+pub type Value = String;
+
+/// The pipeline stages `Repl` drives, mirroring a `Pipeline` read/write pair
+/// but in-process: no Cap'n Proto framing, no subprocess per input.
+pub trait Stages {
+    fn parse(&self, source: &str) -> PipelineResult<Value>;
+    fn type_check(&self, ast: &Value, config: &PipelineConfig) -> PipelineResult<Value>;
+    fn lower(&self, typed_ast: &Value) -> PipelineResult<Value>;
+    fn evaluate(&self, fomega: &Value, config: &PipelineConfig) -> PipelineResult<Value>;
+}
+
+/// An interactive session: live `PipelineConfig`, bindings carried across
+/// inputs so earlier `let`s stay visible, and an optional history file.
+pub struct Repl<S: Stages> {
+    stages: S,
+    config: PipelineConfig,
+    bindings: HashMap<String, Value>,
+    history_file: Option<PathBuf>,
+}
+
+impl<S: Stages> Repl<S> {
+    pub fn new(stages: S, config: PipelineConfig, history_file: Option<PathBuf>) -> Self {
+        Self { stages, config, bindings: HashMap::new(), history_file }
+    }
+
+    /// Bindings captured so far via `let`, visible to later inputs.
+    pub fn bindings(&self) -> &HashMap<String, Value> {
+        &self.bindings
+    }
+
+    /// Read lines from `input` until EOF, evaluating each and writing the
+    /// result (or error) to `output`.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> PipelineResult<()> {
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            self.record_history(line)?;
+            if let Some(command) = line.strip_prefix(':') {
+                if let Err(e) = self.run_meta_command(command, &mut output) {
+                    writeln!(output, "error: {e}")?;
+                }
+                continue;
+            }
+            match self.eval_line(line) {
+                Ok(value) => writeln!(output, "{value}")?,
+                Err(e) => writeln!(output, "error: {e}")?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate one input line, binding it if it's a `let`.
+    fn eval_line(&mut self, line: &str) -> PipelineResult<Value> {
+        if let Some((name, rhs)) = line.strip_prefix("let ").and_then(|rest| rest.split_once('=')) {
+            let value = self.eval_expr(rhs.trim())?;
+            self.bindings.insert(name.trim().to_string(), value.clone());
+            return Ok(value);
+        }
+        self.eval_expr(line)
+    }
+
+    fn eval_expr(&self, source: &str) -> PipelineResult<Value> {
+        let source = format!("{}{source}", self.bindings_prelude());
+        let ast = self.stages.parse(&source)?;
+        let typed = self.stages.type_check(&ast, &self.config)?;
+        let fomega = self.stages.lower(&typed)?;
+        // Only relabel `PipelineError::Effect` — the evaluator's dedicated
+        // signal that a term attempted an effect — not every error an
+        // evaluation can produce; a plain arithmetic or pattern-match
+        // failure under Sandbox purity is still just that error.
+        self.stages.evaluate(&fomega, &self.config).map_err(|e| match e {
+            PipelineError::Effect { message } if self.config.purity_level != PurityLevel::Unrestricted => {
+                PipelineError::Evaluation {
+                    message: format!("effect rejected under {:?} purity: {message}", self.config.purity_level),
+                }
+            }
+            other => other,
+        })
+    }
+
+    /// Earlier `let` bindings, rendered as source text to prepend to the next
+    /// input so a stage that only understands `&str` source — not some
+    /// separate environment argument — still sees them. Sorted by name
+    /// rather than insertion order since `bindings` is a `HashMap`.
+    fn bindings_prelude(&self) -> String {
+        let mut names: Vec<&String> = self.bindings.keys().collect();
+        names.sort();
+        names.iter().map(|name| format!("let {name} = {};\n", self.bindings[*name])).collect()
+    }
+
+    fn run_meta_command<W: Write>(&mut self, command: &str, output: &mut W) -> PipelineResult<()> {
+        let mut parts = command.splitn(2, ' ');
+        match parts.next().unwrap_or("") {
+            "type" => {
+                let expr = parts.next().unwrap_or("").trim();
+                let expr = format!("{}{expr}", self.bindings_prelude());
+                let ast = self.stages.parse(&expr)?;
+                let typed = self.stages.type_check(&ast, &self.config)?;
+                writeln!(output, "{typed}")?;
+            }
+            "load" => {
+                let path = parts.next().unwrap_or("").trim();
+                let source = std::fs::read_to_string(path)?;
+                for line in source.lines() {
+                    if !line.trim().is_empty() {
+                        self.eval_line(line)?;
+                    }
+                }
+            }
+            "config" => {
+                let setting = parts.next().unwrap_or("").trim();
+                self.apply_config(setting)?;
+                writeln!(output, "{:?}", self.config)?;
+            }
+            other => return Err(PipelineError::Evaluation { message: format!("unknown REPL command :{other}") }),
+        }
+        Ok(())
+    }
+
+    fn apply_config(&mut self, setting: &str) -> PipelineResult<()> {
+        let (key, value) = setting.split_once('=').ok_or_else(|| PipelineError::Evaluation {
+            message: format!("expected `:config key=value`, got `:config {setting}`"),
+        })?;
+        match key.trim() {
+            "type_system" => self.config.type_system = parse_type_system(value.trim())?,
+            "purity_level" => self.config.purity_level = parse_purity_level(value.trim())?,
+            "optimization_level" => self.config.optimization_level = parse_optimization_level(value.trim())?,
+            other => return Err(PipelineError::Evaluation { message: format!("unknown config key {other}") }),
+        }
+        Ok(())
+    }
+
+    fn record_history(&self, line: &str) -> PipelineResult<()> {
+        let Some(path) = &self.history_file else { return Ok(()) };
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{line}")?;
+        Ok(())
+    }
+}
+
+fn parse_type_system(s: &str) -> PipelineResult<TypeSystem> {
+    match s {
+        "dynamic" => Ok(TypeSystem::Dynamic),
+        "inferred" => Ok(TypeSystem::Inferred),
+        "gradual" => Ok(TypeSystem::Gradual),
+        "dependent" => Ok(TypeSystem::Dependent),
+        other => Err(PipelineError::Evaluation { message: format!("unknown type system {other}") }),
+    }
+}
+
+fn parse_purity_level(s: &str) -> PipelineResult<PurityLevel> {
+    match s {
+        "pure" => Ok(PurityLevel::Pure),
+        "sandbox" => Ok(PurityLevel::Sandbox),
+        "unrestricted" => Ok(PurityLevel::Unrestricted),
+        other => Err(PipelineError::Evaluation { message: format!("unknown purity level {other}") }),
+    }
+}
+
+fn parse_optimization_level(s: &str) -> PipelineResult<OptimizationLevel> {
+    match s {
+        "debug" => Ok(OptimizationLevel::Debug),
+        "release" => Ok(OptimizationLevel::Release),
+        "aggressive" => Ok(OptimizationLevel::Aggressive),
+        other => Err(PipelineError::Evaluation { message: format!("unknown optimization level {other}") }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum FakeOutcome {
+        Value,
+        PlainError(String),
+        EffectError(String),
+    }
+
+    struct FakeStages(FakeOutcome);
+
+    impl Stages for FakeStages {
+        fn parse(&self, source: &str) -> PipelineResult<Value> {
+            Ok(source.to_string())
+        }
+
+        fn type_check(&self, ast: &Value, _config: &PipelineConfig) -> PipelineResult<Value> {
+            Ok(ast.clone())
+        }
+
+        fn lower(&self, typed_ast: &Value) -> PipelineResult<Value> {
+            Ok(typed_ast.clone())
+        }
+
+        fn evaluate(&self, fomega: &Value, _config: &PipelineConfig) -> PipelineResult<Value> {
+            match &self.0 {
+                FakeOutcome::Value => Ok(fomega.clone()),
+                FakeOutcome::PlainError(message) => Err(PipelineError::Evaluation { message: message.clone() }),
+                FakeOutcome::EffectError(message) => Err(PipelineError::Effect { message: message.clone() }),
+            }
+        }
+    }
+
+    #[test]
+    fn plain_evaluation_error_is_not_relabeled_as_an_effect_rejection() {
+        let stages = FakeStages(FakeOutcome::PlainError("division by zero".to_string()));
+        let mut repl = Repl::new(stages, PipelineConfig::default(), None);
+        let mut output = Vec::new();
+        repl.run("1/0\n".as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.trim(), "error: Evaluation error: division by zero");
+    }
+
+    #[test]
+    fn effect_error_is_relabeled_under_sandbox_purity() {
+        // PipelineConfig::default() is Sandbox, the case this request cares about.
+        let stages = FakeStages(FakeOutcome::EffectError("println".to_string()));
+        let mut repl = Repl::new(stages, PipelineConfig::default(), None);
+        let mut output = Vec::new();
+        repl.run("print 1\n".as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.contains("effect rejected under Sandbox purity: println"),
+            "expected an effect-rejection message, got: {output}"
+        );
+    }
+
+    #[test]
+    fn effect_error_passes_through_unchanged_under_unrestricted_purity() {
+        let stages = FakeStages(FakeOutcome::EffectError("println".to_string()));
+        let mut config = PipelineConfig::default();
+        config.purity_level = PurityLevel::Unrestricted;
+        let mut repl = Repl::new(stages, config, None);
+        let mut output = Vec::new();
+        repl.run("print 1\n".as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.trim(), "error: effect not permitted: println");
+    }
+
+    #[test]
+    fn let_binding_is_recorded_with_its_value() {
+        let stages = FakeStages(FakeOutcome::Value);
+        let mut repl = Repl::new(stages, PipelineConfig::default(), None);
+        // FakeStages passes source through parse/type_check/lower/evaluate
+        // unchanged, so a `let` binds that text.
+        let value = repl.eval_line("let x = 5").unwrap();
+        assert_eq!(value, "5");
+        assert_eq!(repl.bindings().get("x"), Some(&"5".to_string()));
+    }
+
+    #[test]
+    fn a_later_input_actually_sees_an_earlier_let_binding() {
+        let stages = FakeStages(FakeOutcome::Value);
+        let mut repl = Repl::new(stages, PipelineConfig::default(), None);
+        repl.eval_line("let x = 5").unwrap();
+        // FakeStages passes source through unchanged, so the value returned
+        // for evaluating `x` reveals exactly what was handed to `parse` —
+        // proof the prior binding was threaded in, not just recorded.
+        let value = repl.eval_line("x").unwrap();
+        assert_eq!(value, "let x = 5;\nx");
+    }
+
+    #[test]
+    fn multiple_bindings_are_all_threaded_into_a_later_input() {
+        let stages = FakeStages(FakeOutcome::Value);
+        let mut repl = Repl::new(stages, PipelineConfig::default(), None);
+        repl.eval_line("let x = 5").unwrap();
+        repl.eval_line("let y = 6").unwrap();
+        let value = repl.eval_line("x + y").unwrap();
+        // `y` was itself evaluated with `x`'s binding prepended, and the
+        // pass-through fake returns that whole source as `y`'s value.
+        assert_eq!(value, "let x = 5;\nlet y = let x = 5;\n6;\nx + y");
+    }
+}